@@ -0,0 +1,401 @@
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, RwLock, Semaphore};
+use tracing::warn;
+
+pub const BUFFER_SIZE_MB: usize = 5;
+pub const BUFFER_SIZE_BYTES: usize = BUFFER_SIZE_MB * 1024 * 1024;
+pub const CHUNK_SIZE: usize = 4096; // Match Python working version
+pub const MAX_CHUNKS: usize = (BUFFER_SIZE_BYTES / CHUNK_SIZE) + 256; // ~1280 + extra headroom
+pub const PREBUFFER_PERCENT: f32 = 0.60; // 60% - ~3MB, ~17 seconds
+pub const PREBUFFER_CHUNKS: usize = (MAX_CHUNKS as f32 * PREBUFFER_PERCENT) as usize; // ~921 chunks
+pub const MIN_BUFFER_PERCENT: f32 = 0.40; // Low watermark - below this, playout pauses and we mark an underrun
+
+/// Broadcast channel capacity. Generous relative to `PREBUFFER_CHUNKS` so a
+/// client only gets dropped for lagging, not for ordinary jitter.
+const BROADCAST_CAPACITY: usize = MAX_CHUNKS;
+
+/// How long without a `put()` before the occupancy estimate is treated as
+/// stalled and drained to empty, rather than frozen at its last value -
+/// this is what lets a BlueALSA disconnect actually surface as an underrun
+/// instead of leaving `/status` stuck reporting whatever fill it last saw.
+const CAPTURE_STALL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Negotiated PCM format for the current capture session.
+///
+/// Populated once the capture backend has opened the device, so `wav_header()`
+/// can describe what's actually coming off the wire instead of assuming
+/// 44.1kHz/16-bit/stereo.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bits_per_sample: u16,
+}
+
+impl Default for AudioFormat {
+    fn default() -> Self {
+        Self {
+            sample_rate: 44_100,
+            channels: 2,
+            bits_per_sample: 16,
+        }
+    }
+}
+
+/// Recent chunks kept around to prime late-joining clients, plus the
+/// broadcast sender new clients fan out from. Bundled behind one lock so a
+/// `put()` and a `subscribe()` can never interleave and leave a gap between
+/// the history snapshot a client receives and the first chunk its receiver
+/// sees.
+struct FanOut {
+    history: VecDeque<Bytes>,
+    tx: broadcast::Sender<Bytes>,
+}
+
+/// A single client's read position into the fan-out.
+pub struct ClientSubscription {
+    pub id: u64,
+    pub backlog: Vec<Bytes>,
+    pub rx: broadcast::Receiver<Bytes>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClientStatus {
+    pub id: u64,
+    pub lag_events: u64,
+    pub connected_secs: f32,
+}
+
+struct ClientHandle {
+    connected_at: Instant,
+    lag_events: u64,
+}
+
+/// How full the pipe actually is, independent of the late-joiner `history`
+/// ring. `history` is capped at `MAX_CHUNKS` and saturates permanently a
+/// few seconds after startup, so it can't double as an ongoing "is playout
+/// keeping up" signal - `level_bytes` instead decays in real time at the
+/// negotiated bitrate and is topped up by every `put()`.
+struct Occupancy {
+    level_bytes: f64,
+    last_update: Instant,
+    last_put: Instant,
+}
+
+/// Drain `level_bytes` by whatever was consumed over `elapsed_secs` at
+/// `bytes_per_sec`, then add `added_bytes`, clamped to `cap_bytes`. Split
+/// out as a pure function so the watermark arithmetic is unit-testable
+/// without an async runtime or real wall-clock time.
+fn decay_and_add(level_bytes: f64, elapsed_secs: f64, bytes_per_sec: f64, added_bytes: f64, cap_bytes: f64) -> f64 {
+    let drained = (level_bytes - elapsed_secs * bytes_per_sec).max(0.0);
+    (drained + added_bytes).min(cap_bytes)
+}
+
+/// Producer/broadcast audio buffer: the capture task writes chunks in, and
+/// every connected client gets its own read position fanned out from a
+/// `tokio::sync::broadcast` channel rather than racing to pop a shared queue.
+#[derive(Clone)]
+pub struct AudioBuffer {
+    fanout: Arc<RwLock<FanOut>>,
+    stats: Arc<RwLock<BufferStats>>,
+    prebuffer_semaphore: Arc<Semaphore>,
+    format: Arc<RwLock<AudioFormat>>,
+    clients: Arc<RwLock<HashMap<u64, ClientHandle>>>,
+    next_client_id: Arc<AtomicU64>,
+    occupancy: Arc<RwLock<Occupancy>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BufferStats {
+    pub current_size: usize,
+    pub bytes_written: u64,
+    pub bytes_read: u64,
+    pub chunks_written: u64,
+    pub chunks_read: u64,
+    pub is_prebuffered: bool,
+    pub active_clients: usize,
+    pub total_lag_events: u64,
+    pub underrun_count: u64,
+    pub overrun_count: u64,
+}
+
+impl AudioBuffer {
+    pub fn new() -> Self {
+        let (tx, _rx) = broadcast::channel(BROADCAST_CAPACITY);
+        Self {
+            fanout: Arc::new(RwLock::new(FanOut {
+                history: VecDeque::with_capacity(MAX_CHUNKS),
+                tx,
+            })),
+            stats: Arc::new(RwLock::new(BufferStats::default())),
+            prebuffer_semaphore: Arc::new(Semaphore::new(0)),
+            format: Arc::new(RwLock::new(AudioFormat::default())),
+            clients: Arc::new(RwLock::new(HashMap::new())),
+            next_client_id: Arc::new(AtomicU64::new(1)),
+            occupancy: Arc::new(RwLock::new(Occupancy {
+                level_bytes: 0.0,
+                last_update: Instant::now(),
+                last_put: Instant::now(),
+            })),
+        }
+    }
+
+    pub async fn put(&self, data: Bytes) {
+        let data_len = data.len();
+
+        let mut fanout = self.fanout.write().await;
+        // Retiring the oldest retained chunk here is routine ring rotation
+        // for the late-joiner backlog, not a consumer overrun - it happens
+        // continuously once `history` fills, with or without a single
+        // client connected. Real overruns are tracked in `record_lag`,
+        // where a client actually failed to keep up with the broadcast.
+        while fanout.history.len() >= MAX_CHUNKS {
+            fanout.history.pop_front();
+        }
+        fanout.history.push_back(data.clone());
+        // No receivers yet (e.g. capture started before the first client
+        // connects) is not an error - just means nobody's listening live.
+        let _ = fanout.tx.send(data);
+        drop(fanout);
+
+        self.update_occupancy(data_len).await;
+
+        let mut stats = self.stats.write().await;
+        stats.bytes_written += data_len as u64;
+        stats.chunks_written += 1;
+    }
+
+    /// Age the occupancy estimate by wall-clock time (draining it at the
+    /// negotiated bitrate, or to zero outright if capture has stalled
+    /// beyond `CAPTURE_STALL_TIMEOUT`), top it up by `added_bytes` of newly
+    /// captured audio, apply watermark hysteresis against the result, and
+    /// return the resulting fill fraction. Called from `put()` with the new
+    /// chunk's length, and with `0` from any read path that needs a fresh
+    /// reading (so a stalled/disconnected source surfaces as an underrun on
+    /// the next `/status` poll instead of freezing at its last value).
+    async fn update_occupancy(&self, added_bytes: usize) -> f32 {
+        let now = Instant::now();
+        let format = *self.format.read().await;
+        let bytes_per_sec =
+            format.sample_rate as f64 * format.channels as f64 * (format.bits_per_sample as f64 / 8.0);
+
+        let mut occ = self.occupancy.write().await;
+        if added_bytes > 0 {
+            occ.last_put = now;
+        }
+        let elapsed = if now.duration_since(occ.last_put) > CAPTURE_STALL_TIMEOUT {
+            occ.level_bytes = 0.0;
+            0.0
+        } else {
+            now.duration_since(occ.last_update).as_secs_f64()
+        };
+        occ.level_bytes = decay_and_add(
+            occ.level_bytes,
+            elapsed,
+            bytes_per_sec,
+            added_bytes as f64,
+            BUFFER_SIZE_BYTES as f64,
+        );
+        occ.last_update = now;
+        let level_bytes = occ.level_bytes;
+        drop(occ);
+
+        let fill = (level_bytes / BUFFER_SIZE_BYTES as f64) as f32;
+
+        let mut stats = self.stats.write().await;
+        stats.current_size = level_bytes as usize;
+
+        // Hysteresis: only flip Idle->Playing once fill rises above the high
+        // watermark, and only flip Playing->Idle once it drops below the low
+        // watermark, instead of a single threshold that thrashes when fill
+        // oscillates right at the line.
+        if !stats.is_prebuffered && fill >= PREBUFFER_PERCENT {
+            stats.is_prebuffered = true;
+            self.prebuffer_semaphore.add_permits(1000); // Allow many waiters
+        } else if stats.is_prebuffered && fill < MIN_BUFFER_PERCENT {
+            stats.is_prebuffered = false;
+            stats.underrun_count += 1;
+            warn!("Buffer fill dropped below low watermark, pausing playout (underrun)");
+        }
+
+        fill
+    }
+
+    /// Register a new client and hand back its backlog (the most recent
+    /// ~`PREBUFFER_CHUNKS` of retained history, for an instant prebuffer)
+    /// plus a live receiver positioned to continue exactly where the backlog
+    /// ends.
+    pub async fn subscribe(&self) -> ClientSubscription {
+        let fanout = self.fanout.read().await;
+        let skip = fanout.history.len().saturating_sub(PREBUFFER_CHUNKS);
+        let backlog: Vec<Bytes> = fanout.history.iter().skip(skip).cloned().collect();
+        let rx = fanout.tx.subscribe();
+        drop(fanout);
+
+        let id = self.next_client_id.fetch_add(1, Ordering::Relaxed);
+        self.clients.write().await.insert(
+            id,
+            ClientHandle {
+                connected_at: Instant::now(),
+                lag_events: 0,
+            },
+        );
+        self.stats.write().await.active_clients = self.clients.read().await.len();
+
+        ClientSubscription { id, backlog, rx }
+    }
+
+    pub async fn unsubscribe(&self, id: u64) {
+        self.clients.write().await.remove(&id);
+        self.stats.write().await.active_clients = self.clients.read().await.len();
+    }
+
+    /// Record that a client fell far enough behind the broadcast ring that
+    /// it missed chunks (`tokio::sync::broadcast`'s `RecvError::Lagged`).
+    /// Callers drop the client's stream after calling this - a lagged
+    /// client cannot catch back up without skipping audio. This is the
+    /// genuine overrun signal: the producer outpaced that consumer's
+    /// capacity, as opposed to routine history-ring rotation in `put()`.
+    pub async fn record_lag(&self, id: u64, skipped: u64) {
+        warn!("Client {} lagged by {} chunks, dropping connection", id, skipped);
+        if let Some(handle) = self.clients.write().await.get_mut(&id) {
+            handle.lag_events += skipped;
+        }
+        let mut stats = self.stats.write().await;
+        stats.total_lag_events += skipped;
+        stats.overrun_count += 1;
+    }
+
+    pub async fn record_chunk_read(&self, len: usize) {
+        let mut stats = self.stats.write().await;
+        stats.bytes_read += len as u64;
+        stats.chunks_read += 1;
+    }
+
+    pub async fn wait_for_prebuffer(&self, timeout: Duration) -> bool {
+        tokio::time::timeout(timeout, self.prebuffer_semaphore.acquire())
+            .await
+            .is_ok()
+    }
+
+    pub async fn get_stats(&self) -> BufferStats {
+        self.update_occupancy(0).await;
+        self.stats.read().await.clone()
+    }
+
+    /// Whether the buffer is currently above the high watermark and playout
+    /// should be emitting audio (vs. idling through an underrun).
+    pub async fn is_playing(&self) -> bool {
+        self.update_occupancy(0).await;
+        self.stats.read().await.is_prebuffered
+    }
+
+    pub async fn get_client_statuses(&self) -> Vec<ClientStatus> {
+        let clients = self.clients.read().await;
+        clients
+            .iter()
+            .map(|(&id, handle)| ClientStatus {
+                id,
+                lag_events: handle.lag_events,
+                connected_secs: handle.connected_at.elapsed().as_secs_f32(),
+            })
+            .collect()
+    }
+
+    pub async fn get_fill_percentage(&self) -> f32 {
+        self.update_occupancy(0).await * 100.0
+    }
+
+    /// Record the format negotiated with the capture device, so `wav_header()`
+    /// reflects what's actually being captured.
+    pub async fn set_format(&self, format: AudioFormat) {
+        *self.format.write().await = format;
+    }
+
+    pub async fn get_format(&self) -> AudioFormat {
+        *self.format.read().await
+    }
+}
+
+/// Generate a WAV header for the given format, with unknown (streaming) sizes.
+pub fn wav_header(format: AudioFormat) -> [u8; 44] {
+    let block_align = (format.channels * format.bits_per_sample / 8) as u32;
+    let byte_rate = format.sample_rate * block_align;
+    let block_align = block_align as u16;
+
+    let mut header = [0u8; 44];
+    header[0..4].copy_from_slice(b"RIFF");
+    header[4..8].copy_from_slice(&0xFFFFFFFFu32.to_le_bytes()); // File size (unknown)
+    header[8..12].copy_from_slice(b"WAVE");
+    header[12..16].copy_from_slice(b"fmt ");
+    header[16..20].copy_from_slice(&16u32.to_le_bytes()); // fmt chunk size
+    header[20..22].copy_from_slice(&1u16.to_le_bytes()); // Audio format (1 = PCM)
+    header[22..24].copy_from_slice(&format.channels.to_le_bytes());
+    header[24..28].copy_from_slice(&format.sample_rate.to_le_bytes());
+    header[28..32].copy_from_slice(&byte_rate.to_le_bytes());
+    header[32..34].copy_from_slice(&block_align.to_le_bytes());
+    header[34..36].copy_from_slice(&format.bits_per_sample.to_le_bytes());
+    header[36..40].copy_from_slice(b"data");
+    header[40..44].copy_from_slice(&0xFFFFFFFFu32.to_le_bytes()); // Data size (unknown)
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decay_and_add_drains_at_the_given_rate() {
+        // 1 second at 1000 B/s drains 1000 bytes, then the new chunk tops
+        // it back up.
+        let level = decay_and_add(5000.0, 1.0, 1000.0, 200.0, 10_000.0);
+        assert_eq!(level, 4200.0);
+    }
+
+    #[test]
+    fn decay_and_add_never_goes_negative() {
+        let level = decay_and_add(100.0, 10.0, 1000.0, 0.0, 10_000.0);
+        assert_eq!(level, 0.0);
+    }
+
+    #[test]
+    fn decay_and_add_clamps_to_capacity() {
+        let level = decay_and_add(9_900.0, 0.0, 1000.0, 5_000.0, 10_000.0);
+        assert_eq!(level, 10_000.0);
+    }
+
+    #[tokio::test]
+    async fn put_flips_prebuffered_once_fill_crosses_high_watermark() {
+        let buffer = AudioBuffer::new();
+        assert!(!buffer.is_playing().await);
+
+        let chunk = Bytes::from(vec![0u8; (BUFFER_SIZE_BYTES as f64 * 0.7) as usize]);
+        buffer.put(chunk).await;
+
+        assert!(buffer.is_playing().await);
+    }
+
+    #[tokio::test]
+    async fn stalled_capture_drains_to_empty_and_clears_prebuffered() {
+        let buffer = AudioBuffer::new();
+        let chunk = Bytes::from(vec![0u8; (BUFFER_SIZE_BYTES as f64 * 0.7) as usize]);
+        buffer.put(chunk).await;
+        assert!(buffer.is_playing().await);
+
+        // Force the next occupancy read to see a stale `last_put`, as if
+        // the BlueALSA source had gone silent - this should drain the
+        // level to zero and clear `is_prebuffered` on its own, without
+        // another `put()` ever happening.
+        {
+            let mut occ = buffer.occupancy.write().await;
+            occ.last_put -= CAPTURE_STALL_TIMEOUT + Duration::from_millis(1);
+        }
+
+        assert!(!buffer.is_playing().await);
+        assert_eq!(buffer.get_stats().await.current_size, 0);
+    }
+}
@@ -0,0 +1,266 @@
+//! Audio capture backends.
+//!
+//! The default backend opens the BlueALSA PCM directly via the `alsa` crate,
+//! negotiating sample rate/channels/format from the device itself. Building
+//! with `--no-default-features --features subprocess-capture` falls back to
+//! shelling out to `bluealsa-cli`, for systems where linking against ALSA
+//! isn't an option.
+//!
+//! Either way, capture is now driven by the live [`DeviceState`] the D-Bus
+//! watcher maintains: we only attach once a source is actually `Running`,
+//! and a state change (pause, codec switch, disconnect) cancels the current
+//! attempt and re-evaluates instead of polling on a fixed retry timer.
+
+use crate::buffer::{AudioBuffer, CHUNK_SIZE};
+use crate::dbus::DeviceState;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+/// Get Bluetooth device MAC from environment or use any available A2DP source
+pub fn get_target_device() -> Option<String> {
+    std::env::var("BLUETOOTH_MAC").ok()
+}
+
+/// Build the BlueALSA PCM device name ALSA will open for a given MAC
+/// address.
+fn bluealsa_pcm_name(mac: &str) -> String {
+    format!("bluealsa:DEV={},PROFILE=a2dp", mac)
+}
+
+pub async fn audio_capture_task(buffer: AudioBuffer, mut device_rx: watch::Receiver<DeviceState>) {
+    info!("🎤 Starting BlueALSA audio capture (D-Bus event-driven)...");
+
+    loop {
+        let state = device_rx.borrow().clone();
+        if !state.running {
+            info!("⏸️  Waiting for a BlueALSA source to start streaming...");
+            if device_rx.changed().await.is_err() {
+                warn!("Device watcher channel closed, stopping capture task");
+                break;
+            }
+            continue;
+        }
+
+        info!(
+            "📡 Source is running ({:?}, codec={:?}), attaching capture",
+            state.mac, state.codec
+        );
+
+        tokio::select! {
+            result = start_capture(&buffer, &state) => {
+                match result {
+                    Ok(_) => warn!("Audio capture ended normally, re-evaluating device state..."),
+                    Err(e) => error!("❌ Audio capture error: {}", e),
+                }
+            }
+            changed = device_rx.changed() => {
+                if changed.is_err() {
+                    warn!("Device watcher channel closed, stopping capture task");
+                    break;
+                }
+                info!("🔁 Device state changed, re-attaching capture");
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "subprocess-capture"))]
+async fn start_capture(
+    buffer: &AudioBuffer,
+    state: &DeviceState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    native::start_native_capture(buffer, state).await
+}
+
+#[cfg(feature = "subprocess-capture")]
+async fn start_capture(
+    buffer: &AudioBuffer,
+    state: &DeviceState,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    subprocess::start_bluealsa_capture(buffer, state).await
+}
+
+/// Native in-process capture via the `alsa` crate, opening the BlueALSA PCM
+/// by name directly.
+///
+/// `cpal`'s device enumeration only surfaces ALSA hint entries (physical
+/// cards, `default`, etc.) - BlueALSA doesn't register a hint per connected
+/// MAC, so a constructed name like `bluealsa:DEV=...,PROFILE=a2dp` never
+/// shows up in `cpal::Host::input_devices()`. Opening the PCM name directly
+/// with `alsa::pcm::PCM::new` sidesteps enumeration entirely.
+#[cfg(not(feature = "subprocess-capture"))]
+mod native {
+    use super::*;
+    use crate::buffer::AudioFormat;
+    use alsa::pcm::{Access, Format, HwParams, PCM};
+    use alsa::Direction;
+    use bytes::Bytes;
+
+    pub async fn start_native_capture(
+        buffer: &AudioBuffer,
+        state: &DeviceState,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let target_mac = state
+            .mac
+            .clone()
+            .or_else(get_target_device)
+            .unwrap_or_else(|| "F4:04:4C:1A:E5:B9".to_string());
+        let pcm_name = bluealsa_pcm_name(&target_mac);
+        info!("✅ Targeting Bluetooth device: {} ({})", target_mac, pcm_name);
+
+        let pcm_name_for_open = pcm_name.clone();
+        let pcm = tokio::task::spawn_blocking(move || -> Result<PCM, alsa::Error> {
+            let pcm = PCM::new(&pcm_name_for_open, Direction::Capture, false)?;
+            {
+                let hwp = HwParams::any(&pcm)?;
+                hwp.set_access(Access::RWInterleaved)?;
+                hwp.set_format(Format::s16())?;
+                hwp.set_rate_resample(false)?;
+                pcm.hw_params(&hwp)?;
+            }
+            pcm.prepare()?;
+            Ok(pcm)
+        })
+        .await??;
+
+        let hwp = pcm.hw_params_current()?;
+        let sample_rate = hwp.get_rate()?;
+        let channels = hwp.get_channels()? as u16;
+
+        let format = AudioFormat {
+            sample_rate,
+            channels,
+            bits_per_sample: 16,
+        };
+        buffer.set_format(format).await;
+        info!(
+            "📡 Negotiated capture format: {}Hz, {}ch, {}-bit",
+            format.sample_rate, format.channels, format.bits_per_sample
+        );
+
+        info!("📡 Audio capture started, filling buffer...");
+
+        let frame_samples = CHUNK_SIZE / 2;
+        let channels = format.channels as usize;
+        // `alsa::pcm::IO` borrows its `PCM` (`IO<'a, S>(&'a PCM, ...)`), so it
+        // can't be carried across `spawn_blocking` calls alongside the `PCM`
+        // it borrows from - moving both in one shot is a borrow-then-move
+        // error. Instead, move only the owned `PCM` into each blocking
+        // closure and derive a fresh `IO` from it there; the borrow never
+        // outlives the closure it was created in, only `pcm` itself comes
+        // back out.
+        let mut pcm = pcm;
+        loop {
+            let (result, returned_pcm) = tokio::task::spawn_blocking(move || {
+                let mut samples = vec![0i16; frame_samples];
+                let result = pcm.io_i16().and_then(|io| io.readi(&mut samples)).map(|n| {
+                    samples.truncate(n * channels);
+                    samples
+                });
+                (result, pcm)
+            })
+            .await?;
+            pcm = returned_pcm;
+
+            match result {
+                Ok(samples) => {
+                    let bytes: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+                    buffer.put(Bytes::from(bytes)).await;
+                }
+                Err(e) => {
+                    warn!("ALSA read error, attempting recover: {}", e);
+                    if pcm.recover(e.errno() as i32, true).is_err() {
+                        error!("ALSA recover failed, ending capture");
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Legacy subprocess-based capture via `bluealsa-cli`, kept for systems that
+/// can't link against ALSA.
+#[cfg(feature = "subprocess-capture")]
+mod subprocess {
+    use super::*;
+    use bytes::Bytes;
+    use std::time::Instant;
+    use tokio::io::AsyncReadExt;
+    use tokio::process::Command;
+
+    pub async fn start_bluealsa_capture(
+        buffer: &AudioBuffer,
+        state: &DeviceState,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // Find Bluetooth source device (prefer the D-Bus-reported MAC, fall
+        // back to env var / default for systems without the watcher wired up)
+        let target_mac = state
+            .mac
+            .clone()
+            .or_else(get_target_device)
+            .unwrap_or_else(|| "F4:04:4C:1A:E5:B9".to_string());
+        info!("✅ Targeting Bluetooth device: {}", target_mac);
+
+        // Build BlueALSA PCM path: /org/bluealsa/hci0/dev_XX_XX_XX_XX_XX_XX/a2dpsnk/source
+        let bluealsa_path = state.pcm_path.clone().unwrap_or_else(|| {
+            format!(
+                "/org/bluealsa/hci0/dev_{}/a2dpsnk/source",
+                target_mac.replace(':', "_")
+            )
+        });
+
+        info!("📡 Opening BlueALSA PCM: {}", bluealsa_path);
+
+        // Use bluealsa-cli to capture audio directly from BlueALSA
+        let mut child = Command::new("bluealsa-cli")
+            .args(&["open", &bluealsa_path])
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::null())
+            .spawn()?;
+
+        let mut stdout = child.stdout.take().ok_or("Failed to get stdout")?;
+
+        info!("📡 Audio capture started, filling buffer...");
+
+        let mut chunk_buf = vec![0u8; CHUNK_SIZE];
+        let mut chunk_count = 0u64;
+        let mut last_log = Instant::now();
+
+        loop {
+            match stdout.read(&mut chunk_buf).await {
+                Ok(n) if n > 0 => {
+                    buffer.put(Bytes::copy_from_slice(&chunk_buf[..n])).await;
+                    chunk_count += 1;
+
+                    if chunk_count % 100 == 0 || last_log.elapsed() > Duration::from_secs(10) {
+                        let stats = buffer.get_stats().await;
+                        let fill = buffer.get_fill_percentage().await;
+                        info!(
+                            "🔊 Capture | Buffer: {:.1}% ({:.1}MB) | Chunks: {} | Active clients: {}",
+                            fill,
+                            stats.current_size as f32 / (1024.0 * 1024.0),
+                            chunk_count,
+                            stats.active_clients
+                        );
+                        last_log = Instant::now();
+                    }
+                }
+                Ok(_) => {
+                    warn!("Audio stream ended");
+                    break;
+                }
+                Err(e) => {
+                    error!("Read error: {}", e);
+                    break;
+                }
+            }
+        }
+
+        let _ = child.kill().await;
+        Ok(())
+    }
+}
@@ -0,0 +1,332 @@
+//! D-Bus client for BlueALSA/BlueZ discovery and live connection monitoring.
+//!
+//! Replaces the old `bluealsa-aplay --list-pcms` text scraping and the blind
+//! 5-second capture retry loop: we talk to `org.bluealsa` directly, enumerate
+//! its PCM objects up front, then subscribe to `InterfacesAdded`/
+//! `InterfacesRemoved`/`PropertiesChanged` so the server learns about
+//! connect/disconnect/pause/codec changes as they happen and only (re)opens
+//! capture once a source is actually running.
+
+use futures_util::StreamExt;
+use std::collections::HashMap;
+use tokio::sync::watch;
+use tracing::{info, warn};
+use zbus::zvariant::{ObjectPath, OwnedObjectPath, OwnedValue};
+use zbus::{proxy, Connection};
+
+const BLUEALSA_SERVICE: &str = "org.bluealsa";
+
+/// Live view of the turntable's BlueALSA connection, updated in real time as
+/// D-Bus signals arrive.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceState {
+    pub connected: bool,
+    pub mac: Option<String>,
+    pub codec: Option<String>,
+    pub transport: Option<String>,
+    pub running: bool,
+    pub pcm_path: Option<String>,
+}
+
+#[proxy(
+    interface = "org.freedesktop.DBus.ObjectManager",
+    default_service = "org.bluealsa",
+    default_path = "/"
+)]
+trait ObjectManager {
+    fn get_managed_objects(
+        &self,
+    ) -> zbus::Result<HashMap<OwnedObjectPath, HashMap<String, HashMap<String, OwnedValue>>>>;
+
+    #[zbus(signal)]
+    fn interfaces_added(
+        &self,
+        object_path: ObjectPath<'_>,
+        interfaces: HashMap<String, HashMap<String, OwnedValue>>,
+    );
+
+    #[zbus(signal)]
+    fn interfaces_removed(&self, object_path: ObjectPath<'_>, interfaces: Vec<String>);
+}
+
+const PCM_INTERFACE: &str = "org.bluealsa.PCM1";
+
+/// Pull the handful of PCM properties we care about out of the property map
+/// BlueALSA hands back for an `org.bluealsa.PCM1` object.
+fn device_state_from_props(path: &str, props: &HashMap<String, OwnedValue>) -> DeviceState {
+    // `OwnedValue` can hold a non-cloneable `Fd`, so it only offers a
+    // fallible `try_clone()` rather than `Clone` - a plain `.clone()` here
+    // would clone the `&OwnedValue` reference, not the value, and fail to
+    // convert.
+    let get_str = |key: &str| -> Option<String> {
+        props
+            .get(key)
+            .and_then(|v| v.try_clone().ok())
+            .and_then(|v| String::try_from(v).ok())
+    };
+    let running = props
+        .get("Running")
+        .and_then(|v| v.try_clone().ok())
+        .and_then(|v| bool::try_from(v).ok())
+        .unwrap_or(false);
+
+    let mac = get_str("Device").map(|device_path| mac_from_device_path(&device_path));
+
+    DeviceState {
+        connected: true,
+        mac,
+        codec: get_str("Codec"),
+        transport: get_str("Transport"),
+        running,
+        pcm_path: Some(path.to_string()),
+    }
+}
+
+/// BlueALSA's `Device` property is a BlueZ object path like
+/// `/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF`; pull the MAC back out of it.
+fn mac_from_device_path(path: &str) -> String {
+    path.rsplit("dev_")
+        .next()
+        .unwrap_or(path)
+        .replace('_', ":")
+}
+
+/// Does this PCM object belong to the device we care about (or, if no
+/// specific MAC was requested, is it an A2DP source at all)?
+fn matches_target(state: &DeviceState, target_mac: Option<&str>) -> bool {
+    let is_source = state
+        .pcm_path
+        .as_deref()
+        .map(|p| p.ends_with("/source"))
+        .unwrap_or(false);
+    if !is_source {
+        return false;
+    }
+    match (target_mac, &state.mac) {
+        (Some(target), Some(mac)) => mac.eq_ignore_ascii_case(target),
+        (None, _) => true,
+        (Some(_), None) => false,
+    }
+}
+
+/// Connects to the system bus, enumerates existing BlueALSA PCMs, and spawns
+/// a background task that keeps `watch::Receiver<DeviceState>` current as
+/// BlueALSA reports connects/disconnects and codec or `Running` changes.
+pub async fn watch_device(target_mac: Option<String>) -> zbus::Result<watch::Receiver<DeviceState>> {
+    let connection = Connection::system().await?;
+    let manager = ObjectManagerProxy::new(&connection).await?;
+
+    let mut initial = DeviceState::default();
+    for (path, interfaces) in manager.get_managed_objects().await? {
+        if let Some(props) = interfaces.get(PCM_INTERFACE) {
+            let state = device_state_from_props(path.as_str(), props);
+            if matches_target(&state, target_mac.as_deref()) {
+                initial = state;
+                break;
+            }
+        }
+    }
+
+    info!(
+        "📡 D-Bus BlueALSA watcher starting (initial state: connected={}, running={})",
+        initial.connected, initial.running
+    );
+
+    let (tx, rx) = watch::channel(initial);
+
+    tokio::spawn(async move {
+        if let Err(e) = run_watch_loop(connection, manager, target_mac, tx).await {
+            warn!("BlueALSA D-Bus watcher exited: {}", e);
+        }
+    });
+
+    Ok(rx)
+}
+
+async fn run_watch_loop(
+    connection: Connection,
+    manager: ObjectManagerProxy<'_>,
+    target_mac: Option<String>,
+    tx: watch::Sender<DeviceState>,
+) -> zbus::Result<()> {
+    let mut added = manager.receive_interfaces_added().await?;
+    let mut removed = manager.receive_interfaces_removed().await?;
+    // Only one PCM property watcher is ever relevant at a time - each
+    // reconnect replaces the prior attached source. Without aborting the old
+    // task here, every connect/disconnect cycle leaves its
+    // `watch_pcm_properties` task running forever, still polling
+    // `PropertiesChanged` for an object path that's gone.
+    let mut pcm_watcher: Option<tokio::task::JoinHandle<()>> = None;
+
+    loop {
+        tokio::select! {
+            Some(signal) = added.next() => {
+                let args = signal.args()?;
+                if let Some(props) = args.interfaces.get(PCM_INTERFACE) {
+                    let state = device_state_from_props(args.object_path.as_str(), props);
+                    if matches_target(&state, target_mac.as_deref()) {
+                        info!("🔗 BlueALSA source attached: {:?}", state.mac);
+                        if let Some(handle) = pcm_watcher.take() {
+                            handle.abort();
+                        }
+                        pcm_watcher = Some(watch_pcm_properties(
+                            &connection,
+                            OwnedObjectPath::from(args.object_path.to_owned()),
+                            tx.clone(),
+                        ));
+                        let _ = tx.send(state);
+                    }
+                }
+            }
+            Some(signal) = removed.next() => {
+                let args = signal.args()?;
+                if args.interfaces.iter().any(|i| i == PCM_INTERFACE) {
+                    let current = tx.borrow().clone();
+                    if current.pcm_path.as_deref() == Some(args.object_path.as_str()) {
+                        info!("🔌 BlueALSA source detached");
+                        if let Some(handle) = pcm_watcher.take() {
+                            handle.abort();
+                        }
+                        let _ = tx.send(DeviceState::default());
+                    }
+                }
+            }
+            else => break,
+        }
+    }
+
+    Ok(())
+}
+
+/// Subscribe to `PropertiesChanged` on a single PCM object, so codec changes
+/// and pause/resume (`Running`) show up without waiting for it to disconnect
+/// and reconnect. Other properties, such as `Volume` and `Transport`, are not
+/// tracked.
+///
+/// Returns the spawned task's `JoinHandle` so the caller can abort it once
+/// this PCM object stops being the one we care about, rather than leaving it
+/// to poll a stale/gone object path forever.
+fn watch_pcm_properties(
+    connection: &Connection,
+    path: OwnedObjectPath,
+    tx: watch::Sender<DeviceState>,
+) -> tokio::task::JoinHandle<()> {
+    let connection = connection.clone();
+    tokio::spawn(async move {
+        let proxy = match zbus::fdo::PropertiesProxy::builder(&connection)
+            .destination(BLUEALSA_SERVICE)
+            .unwrap()
+            .path(path.clone())
+            .unwrap()
+            .build()
+            .await
+        {
+            Ok(p) => p,
+            Err(e) => {
+                warn!("Failed to watch PCM properties for {}: {}", path, e);
+                return;
+            }
+        };
+
+        let Ok(mut changes) = proxy.receive_properties_changed().await else {
+            return;
+        };
+
+        while let Some(signal) = changes.next().await {
+            let Ok(args) = signal.args() else { continue };
+            if args.interface_name.as_str() != PCM_INTERFACE {
+                continue;
+            }
+            let props: HashMap<String, OwnedValue> = args
+                .changed_properties
+                .iter()
+                .filter_map(|(k, v)| {
+                    let owned = v.try_clone().ok().and_then(|v| OwnedValue::try_from(v).ok())?;
+                    Some((k.to_string(), owned))
+                })
+                .collect();
+            let mut state = tx.borrow().clone();
+            if state.pcm_path.as_deref() != Some(path.as_str()) {
+                continue;
+            }
+            if let Some(codec) = props
+                .get("Codec")
+                .and_then(|v| v.try_clone().ok())
+                .and_then(|v| String::try_from(v).ok())
+            {
+                state.codec = Some(codec);
+            }
+            if let Some(running) = props
+                .get("Running")
+                .and_then(|v| v.try_clone().ok())
+                .and_then(|v| bool::try_from(v).ok())
+            {
+                state.running = running;
+            }
+            if *tx.borrow() != state {
+                let _ = tx.send(state);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mac_from_device_path_extracts_and_reformats_the_mac() {
+        assert_eq!(
+            mac_from_device_path("/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF"),
+            "AA:BB:CC:DD:EE:FF"
+        );
+    }
+
+    #[test]
+    fn mac_from_device_path_falls_back_to_the_input_if_unrecognized() {
+        assert_eq!(mac_from_device_path("garbage"), "garbage");
+    }
+
+    fn source_state(mac: Option<&str>) -> DeviceState {
+        DeviceState {
+            pcm_path: Some("/org/bluealsa/hci0/dev_AA_BB_CC_DD_EE_FF/a2dpsnk/source".to_string()),
+            mac: mac.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn matches_target_rejects_non_source_pcms() {
+        let state = DeviceState {
+            pcm_path: Some("/org/bluealsa/hci0/dev_AA_BB_CC_DD_EE_FF/a2dpsnk/sink".to_string()),
+            ..Default::default()
+        };
+        assert!(!matches_target(&state, None));
+    }
+
+    #[test]
+    fn matches_target_accepts_any_source_when_no_mac_requested() {
+        assert!(matches_target(&source_state(Some("AA:BB:CC:DD:EE:FF")), None));
+    }
+
+    #[test]
+    fn matches_target_compares_mac_case_insensitively() {
+        assert!(matches_target(
+            &source_state(Some("aa:bb:cc:dd:ee:ff")),
+            Some("AA:BB:CC:DD:EE:FF")
+        ));
+    }
+
+    #[test]
+    fn matches_target_rejects_a_different_mac() {
+        assert!(!matches_target(
+            &source_state(Some("11:22:33:44:55:66")),
+            Some("AA:BB:CC:DD:EE:FF")
+        ));
+    }
+
+    #[test]
+    fn matches_target_rejects_a_source_with_no_known_mac_when_one_was_requested() {
+        assert!(!matches_target(&source_state(None), Some("AA:BB:CC:DD:EE:FF")));
+    }
+}
@@ -0,0 +1,565 @@
+//! On-the-fly transcoding of the captured PCM into compressed codecs.
+//!
+//! One background encoder task runs per *active* codec, shared across every
+//! client listening to that codec, so we never encode the same PCM twice for
+//! two Opus listeners. Each encoder subscribes to the raw [`AudioBuffer`] like
+//! any other client and re-publishes its compressed output on its own
+//! broadcast channel; HTTP handlers just subscribe to that.
+
+use crate::buffer::{AudioBuffer, AudioFormat};
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{broadcast, Mutex};
+use tracing::{error, info, warn};
+
+const ENCODED_CHANNEL_CAPACITY: usize = 512;
+
+/// Compressed output codecs available alongside the raw `/stream.wav` path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Codec {
+    Opus,
+    Flac,
+    Mp3,
+}
+
+impl Codec {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Codec::Opus => "audio/ogg",
+            Codec::Flac => "audio/flac",
+            Codec::Mp3 => "audio/mpeg",
+        }
+    }
+
+    /// Pick a codec from an `Accept` header, for clients hitting the plain
+    /// `/stream` endpoint without a codec-specific path.
+    pub fn from_accept(accept: &str) -> Option<Codec> {
+        let accept = accept.to_ascii_lowercase();
+        if accept.contains("audio/ogg") || accept.contains("opus") {
+            Some(Codec::Opus)
+        } else if accept.contains("audio/flac") || accept.contains("flac") {
+            Some(Codec::Flac)
+        } else if accept.contains("audio/mpeg") || accept.contains("mp3") {
+            Some(Codec::Mp3)
+        } else {
+            None
+        }
+    }
+}
+
+/// Lazily-started per-codec encoder fan-out, built on top of the raw
+/// [`AudioBuffer`].
+#[derive(Clone)]
+pub struct EncoderHub {
+    raw: AudioBuffer,
+    channels: Arc<Mutex<HashMap<Codec, broadcast::Sender<Bytes>>>>,
+}
+
+impl EncoderHub {
+    pub fn new(raw: AudioBuffer) -> Self {
+        Self {
+            raw,
+            channels: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Subscribe to a codec's compressed output, starting its background
+    /// encoder task if this is the first listener.
+    pub async fn subscribe(&self, codec: Codec) -> broadcast::Receiver<Bytes> {
+        let mut channels = self.channels.lock().await;
+        if let Some(tx) = channels.get(&codec) {
+            if tx.receiver_count() > 0 {
+                return tx.subscribe();
+            }
+        }
+
+        let (tx, rx) = broadcast::channel(ENCODED_CHANNEL_CAPACITY);
+        channels.insert(codec, tx.clone());
+        drop(channels);
+
+        let raw = self.raw.clone();
+        tokio::spawn(async move {
+            run_encoder(codec, raw, tx).await;
+        });
+
+        rx
+    }
+}
+
+/// Runs until the codec has no listeners left, then exits; `EncoderHub`
+/// restarts it on the next subscribe.
+async fn run_encoder(codec: Codec, raw: AudioBuffer, tx: broadcast::Sender<Bytes>) {
+    info!("Starting {:?} encoder", codec);
+    let format = raw.get_format().await;
+    let mut encoder: Box<dyn FrameEncoder> = match make_encoder(codec, format) {
+        Ok(e) => e,
+        Err(e) => {
+            error!("Failed to start {:?} encoder: {}", codec, e);
+            return;
+        }
+    };
+
+    let subscription = raw.subscribe().await;
+    let client_id = subscription.id;
+    let mut rx = subscription.rx;
+    let mut pending: Vec<u8> = Vec::new();
+
+    for chunk in subscription.backlog {
+        pending.extend_from_slice(&chunk);
+    }
+
+    loop {
+        if tx.receiver_count() == 0 {
+            info!("{:?} encoder has no listeners, stopping", codec);
+            break;
+        }
+
+        match rx.recv().await {
+            Ok(chunk) => {
+                pending.extend_from_slice(&chunk);
+                for frame in encoder.push(&mut pending) {
+                    let _ = tx.send(Bytes::from(frame));
+                }
+            }
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("{:?} encoder lagged behind raw capture by {} chunks", codec, skipped);
+            }
+            Err(broadcast::error::RecvError::Closed) => {
+                warn!("Raw capture channel closed, stopping {:?} encoder", codec);
+                break;
+            }
+        }
+    }
+
+    raw.unsubscribe(client_id).await;
+}
+
+/// A codec-specific encoder that consumes raw interleaved PCM and emits
+/// ready-to-send compressed frames.
+trait FrameEncoder: Send {
+    /// Consume as much of `pending` as forms complete encodable units,
+    /// draining it, and return the encoded frames produced.
+    fn push(&mut self, pending: &mut Vec<u8>) -> Vec<Vec<u8>>;
+}
+
+fn make_encoder(codec: Codec, format: AudioFormat) -> Result<Box<dyn FrameEncoder>, Box<dyn std::error::Error>> {
+    match codec {
+        Codec::Opus => Ok(Box::new(OpusFrameEncoder::new(format)?)),
+        Codec::Flac => Ok(Box::new(FlacFrameEncoder::new(format)?)),
+        Codec::Mp3 => Ok(Box::new(Mp3FrameEncoder::new(format)?)),
+    }
+}
+
+/// libopus only accepts 8/12/16/24/48kHz; the negotiated capture rate
+/// (44.1kHz for the common A2DP/SBC source) is often not one of them, so pick
+/// the closest one and resample into it before encoding.
+const OPUS_SUPPORTED_RATES: [u32; 5] = [8_000, 12_000, 16_000, 24_000, 48_000];
+
+fn nearest_opus_rate(sample_rate: u32) -> u32 {
+    *OPUS_SUPPORTED_RATES
+        .iter()
+        .min_by_key(|&&rate| (rate as i64 - sample_rate as i64).abs())
+        .unwrap_or(&48_000)
+}
+
+/// Opus requires exact 20ms frames of interleaved i16 samples, so we
+/// linearly resample the captured PCM to a libopus-supported rate, then
+/// repacketize into 20ms boundaries and carry any remainder to the next
+/// `push()`.
+struct OpusFrameEncoder {
+    encoder: opus::Encoder,
+    frame_bytes: usize,
+    ogg: OggOpusMuxer,
+    channels: usize,
+    source_rate: u32,
+    target_rate: u32,
+    /// Resampled (target-rate) samples not yet consumed into a full 20ms
+    /// Opus frame.
+    resampled: Vec<i16>,
+    /// Fractional position (in source-rate frames) of the next output
+    /// sample, carried across `push()` calls so resampling stays
+    /// continuous across chunk boundaries instead of resetting phase.
+    resample_pos: f64,
+}
+
+impl OpusFrameEncoder {
+    fn new(format: AudioFormat) -> Result<Self, Box<dyn std::error::Error>> {
+        let channels_count = format.channels as usize;
+        let channels = match format.channels {
+            1 => opus::Channels::Mono,
+            _ => opus::Channels::Stereo,
+        };
+        let target_rate = nearest_opus_rate(format.sample_rate);
+        let encoder = opus::Encoder::new(target_rate, channels, opus::Application::Audio)?;
+        let samples_per_20ms = (target_rate / 50) as usize * channels_count;
+        let frame_bytes = samples_per_20ms * 2; // i16 samples
+
+        if target_rate != format.sample_rate {
+            info!(
+                "Resampling capture ({}Hz) to {}Hz for Opus",
+                format.sample_rate, target_rate
+            );
+        }
+
+        Ok(Self {
+            encoder,
+            frame_bytes,
+            ogg: OggOpusMuxer::new(format),
+            channels: channels_count,
+            source_rate: format.sample_rate,
+            target_rate,
+            resampled: Vec::new(),
+            resample_pos: 0.0,
+        })
+    }
+
+    /// Linearly resample whatever whole interleaved source frames are
+    /// available in `pending` into `self.resampled`, draining the bytes
+    /// consumed.
+    fn resample(&mut self, pending: &mut Vec<u8>) {
+        let bytes_per_frame = self.channels * 2;
+        let available = pending.len() / bytes_per_frame;
+        if available == 0 {
+            return;
+        }
+
+        let usable = available * bytes_per_frame;
+        let frames: Vec<Vec<i16>> = pending[..usable]
+            .chunks_exact(bytes_per_frame)
+            .map(|f| f.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect())
+            .collect();
+        pending.drain(..usable);
+
+        let ratio = self.source_rate as f64 / self.target_rate as f64;
+        let mut pos = self.resample_pos;
+        while (pos as usize) < frames.len() {
+            let idx = pos as usize;
+            let frac = pos - idx as f64;
+            let cur = &frames[idx];
+            let next = frames.get(idx + 1).unwrap_or(cur);
+            for ch in 0..self.channels {
+                let a = cur[ch] as f64;
+                let b = next[ch] as f64;
+                self.resampled.push((a + (b - a) * frac).round() as i16);
+            }
+            pos += ratio;
+        }
+        // Keep the fractional remainder relative to the frames we just
+        // consumed so the next call picks up mid-sample instead of
+        // snapping back to an integer boundary.
+        self.resample_pos = pos - frames.len() as f64;
+    }
+}
+
+impl FrameEncoder for OpusFrameEncoder {
+    fn push(&mut self, pending: &mut Vec<u8>) -> Vec<Vec<u8>> {
+        self.resample(pending);
+
+        let mut out = Vec::new();
+        let mut out_buf = [0u8; 4000];
+        let frame_samples = self.frame_bytes / 2;
+
+        while self.resampled.len() >= frame_samples {
+            let frame_pcm: Vec<i16> = self.resampled.drain(..frame_samples).collect();
+            match self.encoder.encode(&frame_pcm, &mut out_buf) {
+                Ok(len) => {
+                    out.extend(self.ogg.packetize(&out_buf[..len], frame_pcm.len() / self.channels))
+                }
+                Err(e) => error!("Opus encode error: {}", e),
+            }
+        }
+
+        out
+    }
+}
+
+/// A `Write` sink backed by a shared buffer, so we can drain whatever pages
+/// `ogg::writing::PacketWriter` has flushed after each packet it's handed.
+struct SharedSink(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for SharedSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Wraps raw Opus packets into an Ogg Opus stream (RFC 7845 ID/comment
+/// headers followed by one audio packet per Ogg page).
+///
+/// Per RFC 7845, Opus's internal sample rate is always reported as 48kHz in
+/// the ID header regardless of the stream's actual encoding rate - decoders
+/// resample from whatever `pre-skip`/rate the encoder used internally, so
+/// this is informational only and doesn't need to track `target_rate`.
+struct OggOpusMuxer {
+    writer: ogg::writing::PacketWriter<'static, SharedSink>,
+    sink: Arc<std::sync::Mutex<Vec<u8>>>,
+    granule_position: u64,
+    wrote_headers: bool,
+}
+
+impl OggOpusMuxer {
+    fn new(_format: AudioFormat) -> Self {
+        let sink = Arc::new(std::sync::Mutex::new(Vec::new()));
+        Self {
+            writer: ogg::writing::PacketWriter::new(SharedSink(sink.clone())),
+            sink,
+            granule_position: 0,
+            wrote_headers: false,
+        }
+    }
+
+    fn packetize(&mut self, opus_packet: &[u8], frame_samples: usize) -> Vec<Vec<u8>> {
+        const SERIAL: u32 = 0x4F505553; // "OPUS"
+
+        if !self.wrote_headers {
+            let _ = self.writer.write_packet(
+                opus_id_header(),
+                SERIAL,
+                ogg::writing::PacketWriteEndInfo::EndPage,
+                0,
+            );
+            let _ = self.writer.write_packet(
+                opus_comment_header(),
+                SERIAL,
+                ogg::writing::PacketWriteEndInfo::EndPage,
+                0,
+            );
+            self.wrote_headers = true;
+        }
+
+        self.granule_position += frame_samples as u64;
+        let _ = self.writer.write_packet(
+            opus_packet.to_vec(),
+            SERIAL,
+            ogg::writing::PacketWriteEndInfo::EndPage,
+            self.granule_position,
+        );
+
+        let mut sink = self.sink.lock().unwrap();
+        if sink.is_empty() {
+            Vec::new()
+        } else {
+            vec![std::mem::take(&mut *sink)]
+        }
+    }
+}
+
+fn opus_id_header() -> Vec<u8> {
+    let mut h = Vec::with_capacity(19);
+    h.extend_from_slice(b"OpusHead");
+    h.push(1); // version
+    h.push(2); // channel count (negotiated at runtime; 2 is the common case)
+    h.extend_from_slice(&0u16.to_le_bytes()); // pre-skip
+    h.extend_from_slice(&48000u32.to_le_bytes()); // original sample rate (informational)
+    h.extend_from_slice(&0i16.to_le_bytes()); // output gain
+    h.push(0); // channel mapping family
+    h
+}
+
+fn opus_comment_header() -> Vec<u8> {
+    let mut h = Vec::new();
+    h.extend_from_slice(b"OpusTags");
+    let vendor = b"bluetooth-to-http-stream";
+    h.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    h.extend_from_slice(vendor);
+    h.extend_from_slice(&0u32.to_le_bytes()); // no user comments
+    h
+}
+
+/// PCM is gathered into fixed blocks of `block_size` frames; each complete
+/// block is encoded on its own, and a partial block waits for the next
+/// `push()`.
+struct FlacFrameEncoder {
+    format: AudioFormat,
+    block_size: usize,
+    // `encode_with_fixed_block_size` only accepts a verified config, and
+    // verification is fallible, so it's done once up front in `new()`
+    // rather than re-attempted (and silently ignored) on every block.
+    config: flacenc::error::Verified<flacenc::config::Encoder>,
+}
+
+impl FlacFrameEncoder {
+    fn new(format: AudioFormat) -> Result<Self, Box<dyn std::error::Error>> {
+        let config = flacenc::config::Encoder::default()
+            .into_verified()
+            .map_err(|(_, e)| format!("invalid FLAC encoder config: {:?}", e))?;
+        Ok(Self {
+            format,
+            block_size: 4096,
+            config,
+        })
+    }
+}
+
+impl FrameEncoder for FlacFrameEncoder {
+    fn push(&mut self, pending: &mut Vec<u8>) -> Vec<Vec<u8>> {
+        // `BitRepr::write` is what puts an encoded `FlacStream` onto a
+        // `BitSink` - it's a trait method, so it has to be in scope even
+        // though `stream` is never named by that trait.
+        use flacenc::component::BitRepr;
+
+        let bytes_per_block = self.block_size * self.format.channels as usize * 2;
+        let mut out = Vec::new();
+
+        while pending.len() >= bytes_per_block {
+            let block: Vec<i32> = pending[..bytes_per_block]
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]) as i32)
+                .collect();
+            pending.drain(..bytes_per_block);
+
+            let source = flacenc::source::MemSource::from_samples(
+                &block,
+                self.format.channels as usize,
+                16,
+                self.format.sample_rate as usize,
+            );
+            match flacenc::encode_with_fixed_block_size(&self.config, source, self.block_size) {
+                Ok(stream) => {
+                    let mut sink = flacenc::bitsink::ByteSink::new();
+                    if stream.write(&mut sink).is_ok() {
+                        out.push(sink.as_slice().to_vec());
+                    }
+                }
+                Err(e) => error!("FLAC encode error: {:?}", e),
+            }
+        }
+
+        out
+    }
+}
+
+/// MP3 via LAME handles arbitrary-size PCM buffers internally, so we just
+/// forward whatever has accumulated since the last push.
+struct Mp3FrameEncoder {
+    encoder: mp3lame_encoder::Encoder,
+}
+
+impl Mp3FrameEncoder {
+    fn new(format: AudioFormat) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut builder = mp3lame_encoder::Builder::new().ok_or("failed to init LAME builder")?;
+        builder.set_num_channels(format.channels as u8)?;
+        builder.set_sample_rate(format.sample_rate)?;
+        builder.set_brate(mp3lame_encoder::Bitrate::Kbps128)?;
+        builder.set_quality(mp3lame_encoder::Quality::Good)?;
+        let encoder = builder.build()?;
+        Ok(Self { encoder })
+    }
+}
+
+impl FrameEncoder for Mp3FrameEncoder {
+    fn push(&mut self, pending: &mut Vec<u8>) -> Vec<Vec<u8>> {
+        // Keep samples paired so we never split a stereo frame mid-sample.
+        let usable_len = pending.len() - (pending.len() % 4);
+        if usable_len == 0 {
+            return Vec::new();
+        }
+
+        let samples: Vec<i16> = pending[..usable_len]
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        pending.drain(..usable_len);
+
+        // `Encoder::encode` writes into uninitialized output space; size the
+        // buffer per LAME's own worst-case formula and only mark the bytes
+        // it actually wrote as initialized.
+        let mut mp3_out = Vec::with_capacity(mp3lame_encoder::max_required_buffer_size(samples.len()));
+        match self
+            .encoder
+            .encode(mp3lame_encoder::InterleavedPcm(&samples), mp3_out.spare_capacity_mut())
+        {
+            Ok(written) => {
+                // SAFETY: `encode` just initialized exactly `written` bytes
+                // at the front of the spare capacity we handed it.
+                unsafe { mp3_out.set_len(written) };
+                vec![mp3_out]
+            }
+            Err(e) => {
+                error!("MP3 encode error: {:?}", e);
+                Vec::new()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearest_opus_rate_snaps_common_capture_rates() {
+        assert_eq!(nearest_opus_rate(44_100), 48_000);
+        assert_eq!(nearest_opus_rate(48_000), 48_000);
+        assert_eq!(nearest_opus_rate(16_000), 16_000);
+        assert_eq!(nearest_opus_rate(22_050), 24_000);
+    }
+
+    fn opus_encoder_for_test(source_rate: u32, channels: u16) -> OpusFrameEncoder {
+        OpusFrameEncoder::new(AudioFormat {
+            sample_rate: source_rate,
+            channels,
+            bits_per_sample: 16,
+        })
+        .expect("opus encoder should init for a supported channel count")
+    }
+
+    fn samples_to_bytes(samples: &[i16]) -> Vec<u8> {
+        samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn resample_passthrough_at_matching_rate() {
+        let mut enc = opus_encoder_for_test(48_000, 1);
+        let input = [100i16, 200, 300, 400];
+        let mut pending = samples_to_bytes(&input);
+
+        enc.resample(&mut pending);
+
+        assert!(pending.is_empty());
+        assert_eq!(enc.resampled, input.to_vec());
+    }
+
+    #[test]
+    fn resample_halves_sample_count_when_downsampling_by_two() {
+        let mut enc = opus_encoder_for_test(48_000, 1);
+        enc.target_rate = 24_000;
+        let input: Vec<i16> = (0..10).map(|i| i * 10).collect();
+        let mut pending = samples_to_bytes(&input);
+
+        enc.resample(&mut pending);
+
+        assert!(pending.is_empty());
+        // 10 source frames at a 2:1 source:target ratio should yield ~5
+        // output frames, not drift wildly from that.
+        assert!((4..=6).contains(&enc.resampled.len()));
+    }
+
+    #[test]
+    fn resample_carries_fractional_phase_across_calls() {
+        // Resampling the same samples in one call vs. two separate calls
+        // should produce the same total output length - phase must not
+        // reset at call boundaries.
+        let input: Vec<i16> = (0..12).map(|i| i * 5).collect();
+
+        let mut one_shot = opus_encoder_for_test(48_000, 1);
+        one_shot.target_rate = 44_100;
+        let mut all_at_once = samples_to_bytes(&input);
+        one_shot.resample(&mut all_at_once);
+
+        let mut split = opus_encoder_for_test(48_000, 1);
+        split.target_rate = 44_100;
+        let mut first_half = samples_to_bytes(&input[..6]);
+        let mut second_half = samples_to_bytes(&input[6..]);
+        split.resample(&mut first_half);
+        split.resample(&mut second_half);
+
+        assert_eq!(one_shot.resampled.len(), split.resampled.len());
+    }
+}
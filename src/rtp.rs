@@ -0,0 +1,183 @@
+//! RTP/multicast output, for LAN whole-home sync: many speakers join one
+//! multicast group and stay in lockstep, instead of each opening its own
+//! `/stream` connection.
+//!
+//! Packetizes raw PCM from the [`AudioBuffer`] as RTP per RFC 3551 (L16
+//! big-endian PCM), gated by the buffer's low/high watermark hysteresis so a
+//! stalled capture pauses multicast output instead of sending stale/silent
+//! packets.
+
+use crate::buffer::AudioBuffer;
+use bytes::Bytes;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tracing::{error, info, warn};
+
+const DEFAULT_MULTICAST_ADDR: &str = "239.1.1.1";
+const DEFAULT_MULTICAST_PORT: u16 = 5004;
+const RTP_VERSION: u8 = 2;
+// RFC 3551 static payload type for L16/44100/stereo; falls back to a dynamic
+// type for any other negotiated format.
+const PT_L16_STEREO_44100: u8 = 10;
+const PT_L16_MONO_44100: u8 = 11;
+const PT_DYNAMIC: u8 = 96;
+
+/// Resolve the multicast destination from `RTP_MULTICAST_ADDR` /
+/// `RTP_MULTICAST_PORT`, falling back to a sane LAN default.
+pub fn multicast_target() -> SocketAddr {
+    let addr = std::env::var("RTP_MULTICAST_ADDR").unwrap_or_else(|_| DEFAULT_MULTICAST_ADDR.to_string());
+    let port = std::env::var("RTP_MULTICAST_PORT")
+        .ok()
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(DEFAULT_MULTICAST_PORT);
+    format!("{}:{}", addr, port)
+        .parse()
+        .unwrap_or_else(|_| SocketAddr::from(([239, 1, 1, 1], DEFAULT_MULTICAST_PORT)))
+}
+
+fn payload_type(sample_rate: u32, channels: u16) -> u8 {
+    match (sample_rate, channels) {
+        (44_100, 2) => PT_L16_STEREO_44100,
+        (44_100, 1) => PT_L16_MONO_44100,
+        _ => PT_DYNAMIC,
+    }
+}
+
+fn rtp_header(seq: u16, timestamp: u32, ssrc: u32, payload_type: u8) -> [u8; 12] {
+    let mut header = [0u8; 12];
+    header[0] = RTP_VERSION << 6; // version=2, padding=0, extension=0, CSRC count=0
+    header[1] = payload_type & 0x7F; // marker=0
+    header[2..4].copy_from_slice(&seq.to_be_bytes());
+    header[4..8].copy_from_slice(&timestamp.to_be_bytes());
+    header[8..12].copy_from_slice(&ssrc.to_be_bytes());
+    header
+}
+
+/// Background task: packetizes the capture buffer's raw PCM into RTP and
+/// sends it to the configured multicast group, pausing output whenever the
+/// buffer drops into underrun.
+pub async fn run_rtp_sender(buffer: AudioBuffer, target: SocketAddr) {
+    let socket = match UdpSocket::bind("0.0.0.0:0").await {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to bind RTP multicast socket: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = socket.set_multicast_ttl_v4(4) {
+        warn!("Failed to set multicast TTL: {}", e);
+    }
+    if let Err(e) = socket.connect(target).await {
+        error!("Failed to connect RTP socket to {}: {}", target, e);
+        return;
+    }
+
+    info!("📡 RTP multicast sender targeting {}", target);
+
+    let ssrc: u32 = rand::random();
+    let mut seq: u16 = rand::random();
+    let mut timestamp: u32 = rand::random();
+
+    let subscription = buffer.subscribe().await;
+    let mut client_id = subscription.id;
+    let mut rx = subscription.rx;
+    let mut pending: Vec<u8> = subscription.backlog.iter().flat_map(|b| b.to_vec()).collect();
+
+    loop {
+        if !buffer.is_playing().await {
+            // Below the low watermark (or not yet past the high watermark) -
+            // idle rather than send stale/silent audio. Capture keeps
+            // calling `buffer.put()` the whole time we're idle, so the
+            // broadcast receiver accumulates a backlog in the channel even
+            // though we've stopped draining it; clearing just `pending`
+            // wouldn't touch that, so re-subscribe to get a fresh receiver
+            // positioned at the current tail instead of bursting through
+            // whatever queued up while paused.
+            pending.clear();
+            while !buffer.is_playing().await {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            buffer.unsubscribe(client_id).await;
+            let subscription = buffer.subscribe().await;
+            client_id = subscription.id;
+            rx = subscription.rx;
+        }
+
+        let chunk: Bytes = match rx.recv().await {
+            Ok(chunk) => chunk,
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("RTP sender lagged behind capture by {} chunks", skipped);
+                continue;
+            }
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => {
+                warn!("Capture fan-out closed, stopping RTP sender");
+                buffer.unsubscribe(client_id).await;
+                return;
+            }
+        };
+        pending.extend_from_slice(&chunk);
+
+        let format = buffer.get_format().await;
+        let pt = payload_type(format.sample_rate, format.channels);
+        let samples_per_packet = (format.sample_rate / 50) as usize * format.channels as usize; // 20ms
+        let frame_bytes = samples_per_packet * 2;
+
+        while pending.len() >= frame_bytes {
+            let frame: Vec<u8> = pending.drain(..frame_bytes).collect();
+            // Native capture is little-endian i16; L16 payload is big-endian.
+            let mut payload = Vec::with_capacity(frame.len());
+            for sample in frame.chunks_exact(2) {
+                let value = i16::from_le_bytes([sample[0], sample[1]]);
+                payload.extend_from_slice(&value.to_be_bytes());
+            }
+
+            let header = rtp_header(seq, timestamp, ssrc, pt);
+            let mut packet = Vec::with_capacity(header.len() + payload.len());
+            packet.extend_from_slice(&header);
+            packet.extend_from_slice(&payload);
+
+            if let Err(e) = socket.send(&packet).await {
+                warn!("RTP send failed: {}", e);
+            }
+
+            seq = seq.wrapping_add(1);
+            timestamp = timestamp.wrapping_add(samples_per_packet as u32 / format.channels.max(1) as u32);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload_type_uses_the_static_rfc3551_types_for_cd_quality_audio() {
+        assert_eq!(payload_type(44_100, 2), PT_L16_STEREO_44100);
+        assert_eq!(payload_type(44_100, 1), PT_L16_MONO_44100);
+    }
+
+    #[test]
+    fn payload_type_falls_back_to_dynamic_for_other_formats() {
+        assert_eq!(payload_type(48_000, 2), PT_DYNAMIC);
+        assert_eq!(payload_type(44_100, 6), PT_DYNAMIC);
+    }
+
+    #[test]
+    fn rtp_header_lays_out_fields_per_rfc3550() {
+        let header = rtp_header(0x1234, 0xdeadbeef, 0x0a0b0c0d, PT_L16_STEREO_44100);
+        assert_eq!(header[0], RTP_VERSION << 6);
+        assert_eq!(header[1], PT_L16_STEREO_44100);
+        assert_eq!(&header[2..4], &0x1234u16.to_be_bytes());
+        assert_eq!(&header[4..8], &0xdeadbeefu32.to_be_bytes());
+        assert_eq!(&header[8..12], &0x0a0b0c0du32.to_be_bytes());
+    }
+
+    #[test]
+    fn rtp_header_masks_the_payload_type_to_seven_bits() {
+        // Marker bit (0x80) must never leak in from a payload type value we
+        // didn't intend to set it for.
+        let header = rtp_header(0, 0, 0, 0xFF);
+        assert_eq!(header[1], 0x7F);
+    }
+}